@@ -1,7 +1,7 @@
-use itertools::Itertools;
 use std::error::Error;
 
 use std::io::{self, stdout};
+use std::net::ToSocketAddrs;
 
 use crossterm::event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode};
 use crossterm::execute;
@@ -11,17 +11,19 @@ use crossterm::terminal::{
 use tui::backend::{Backend, CrosstermBackend};
 use tui::layout::{Constraint, Direction, Layout, Rect};
 use tui::style::{Color, Modifier, Style};
-use tui::text::{Span, Spans, Text};
+use tui::text::{Span, Spans};
 use tui::widgets::{Block, Borders, List, ListItem, Paragraph, Tabs};
 use tui::{Frame, Terminal};
 use unicode_width::UnicodeWidthStr;
 
 mod server;
-use server::Server;
+mod upnp;
+use server::{Server, ServerConfig};
 
 enum InputMode {
     Normal,
     Editing,
+    Dialing,
 }
 
 /// App holds the state of the application
@@ -34,30 +36,40 @@ struct App {
     scroll: usize,
     // The current chat that is being viewed
     current_chat: Option<usize>,
+    // Set when the last dial attempt couldn't be resolved, cleared on the next
+    // successful one; shown to the user instead of silently dropping the dial.
+    dial_error: Option<String>,
     // The initialized server
     server: Server,
 }
 
 // TODO: This should have a reference to the TCP streams and the associated messages for each connection
 impl App {
-    fn new() -> App {
+    fn new(config: ServerConfig) -> App {
         App {
             input: String::new(),
             input_mode: InputMode::Normal,
             scroll: 0,
             current_chat: None,
-            server: Server::new(),
+            dial_error: None,
+            server: Server::new(config),
         }
     }
 }
 
 impl App {
+    // `current_chat` is a position in the tab list, not a connection token:
+    // peers disconnecting can leave gaps in the underlying slot indices.
+    fn current_token(&self) -> Option<usize> {
+        let indices = self.server.connection_indices();
+        self.current_chat.and_then(|n| indices.get(n).copied())
+    }
+
     fn next(&mut self) {
-        let conns = self.server.connections.lock().unwrap();
-        if conns.len() > 0 {
-            self.current_chat = Some((self.current_chat.unwrap_or(0) + 1) % conns.len())
+        let count = self.server.connection_indices().len();
+        if count > 0 {
+            self.current_chat = Some((self.current_chat.unwrap_or(0) + 1) % count)
         }
-        drop(conns);
     }
 
     fn previous(&mut self) {
@@ -65,8 +77,8 @@ impl App {
             if n > 0 {
                 self.current_chat = Some(n - 1);
             } else {
-                let conns = self.server.connections.lock().unwrap();
-                self.current_chat = Some(conns.len() - 1);
+                let count = self.server.connection_indices().len();
+                self.current_chat = Some(count - 1);
             }
         }
     }
@@ -79,21 +91,51 @@ impl App {
         }
     }
     fn scroll_down(&mut self) {
-        if self.scroll
-            < self
-                .server
-                .get_messages(self.current_chat.unwrap_or(0))
-                .iter()
-                .len()
-        {
+        let message_count = self
+            .current_token()
+            .and_then(|token| self.server.get_messages(token))
+            .map(|messages| messages.len())
+            .unwrap_or(0);
+        if self.scroll < message_count {
             self.scroll += 1;
         }
     }
 }
 
+// Parses `--bind <addr>` and `--no-upnp` out of the process arguments into a
+// `ServerConfig`, falling back to its defaults for anything not given.
+// Unrecognised arguments or an unparseable `--bind` address are reported to
+// stderr and exit the process, rather than silently falling back.
+fn parse_args() -> ServerConfig {
+    let mut config = ServerConfig::default();
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bind" => {
+                let value = args.next().unwrap_or_else(|| {
+                    eprintln!("--bind requires an address, e.g. --bind 0.0.0.0:13265");
+                    std::process::exit(1);
+                });
+                config.bind_addr = value.parse().unwrap_or_else(|_| {
+                    eprintln!("'{}' is not a valid bind address", value);
+                    std::process::exit(1);
+                });
+            }
+            "--no-upnp" => config.enable_upnp = false,
+            other => {
+                eprintln!("unrecognised argument '{}'", other);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    config
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     // Initialize the app state
-    let app = App::new();
+    let app = App::new(parse_args());
 
     // Star the server to listen for incoming connections
     app.server.listen();
@@ -132,15 +174,16 @@ fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Result<(
     loop {
         terminal.draw(|f| ui(f, &app))?;
 
-        // If at least one connection exists, set the current chat to that connection token value
-        if 
-
         if let Event::Key(key) = event::read()? {
             match app.input_mode {
                 InputMode::Normal => match key.code {
                     KeyCode::Char('e') => {
                         app.input_mode = InputMode::Editing;
                     }
+                    KeyCode::Char('d') => {
+                        app.input.clear();
+                        app.input_mode = InputMode::Dialing;
+                    }
                     KeyCode::Char('q') => {
                         return Ok(());
                     }
@@ -152,8 +195,8 @@ fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Result<(
                 },
                 InputMode::Editing => match key.code {
                     KeyCode::Enter => {
-                        if let Some(n) = app.current_chat {
-                            app.server.send_message(n + 1, app.input.drain(..).as_str())
+                        if let Some(token) = app.current_token() {
+                            app.server.send_message(token, app.input.drain(..).as_str())
                         }
                     }
                     KeyCode::Char(c) => {
@@ -167,6 +210,36 @@ fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Result<(
                     }
                     _ => {}
                 },
+                InputMode::Dialing => match key.code {
+                    KeyCode::Enter => {
+                        let target = app.input.drain(..);
+                        // `target` is "host:port" and may be a hostname, so resolve it
+                        // through the OS resolver rather than only accepting a literal
+                        // `SocketAddr`; take the first address it comes back with.
+                        match target.as_str().to_socket_addrs().ok().and_then(|mut a| a.next()) {
+                            Some(addr) => {
+                                app.server.dial(addr);
+                                app.dial_error = None;
+                            }
+                            None => {
+                                app.dial_error =
+                                    Some(format!("couldn't resolve '{}'", target.as_str()));
+                            }
+                        }
+                        app.input_mode = InputMode::Normal;
+                    }
+                    KeyCode::Char(c) => {
+                        app.input.push(c);
+                    }
+                    KeyCode::Backspace => {
+                        app.input.pop();
+                    }
+                    KeyCode::Esc => {
+                        app.input.clear();
+                        app.input_mode = InputMode::Normal;
+                    }
+                    _ => {}
+                },
             }
         }
     }
@@ -179,17 +252,21 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &App) {
         .constraints([Constraint::Length(3), Constraint::Min(0)].as_ref())
         .split(f.size());
 
-    let connections = app.server.connections.lock().unwrap();
-    let titles = connections
-        .keys()
-        .sorted()
+    let titles = app
+        .server
+        .connection_indices()
+        .into_iter()
         .map(|t| format!("{}", t))
         .map(|t| Spans::from(Span::styled(t, Style::default().fg(Color::Green))))
         .collect();
-    drop(connections);
 
     let tabs = Tabs::new(titles)
-        .block(Block::default().borders(Borders::ALL).title("Connections"))
+        .block(
+            Block::default().borders(Borders::ALL).title(format!(
+                "Connections ({})",
+                app.server.number_of_connections()
+            )),
+        )
         .select(app.current_chat.unwrap_or(0))
         .style(Style::default().fg(Color::Cyan))
         .highlight_style(
@@ -199,25 +276,23 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &App) {
         );
     f.render_widget(tabs, chunks[0]);
 
-    if let Some(n) = app.current_chat {
-        draw_chat(f, app, n, chunks[1])
-    }
+    // Dialing doesn't require an existing connection, so the input box (and
+    // any dial error) is drawn even when there's no current chat to show.
+    draw_chat(f, app, app.current_token(), chunks[1])
 }
 
-fn draw_chat<B: Backend>(f: &mut Frame<B>, app: &App, current_chat: usize, area: Rect) {
+fn draw_chat<B: Backend>(f: &mut Frame<B>, app: &App, current_chat: Option<usize>, area: Rect) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([Constraint::Percentage(80), Constraint::Percentage(20)].as_ref())
         .split(area);
 
-    // Render the block
-    let block = Block::default()
-        .borders(Borders::ALL)
-        .title(format!("Connection {}", current_chat));
-
-    let messages = app.server.get_messages(current_chat + 1);
-    match messages {
-        Some(messages) => {
+    match current_chat.and_then(|current_chat| {
+        app.server
+            .get_messages(current_chat)
+            .map(|messages| (current_chat, messages))
+    }) {
+        Some((current_chat, messages)) => {
             let messages_list_item: Vec<ListItem> = messages
                 .iter()
                 .enumerate()
@@ -230,19 +305,28 @@ fn draw_chat<B: Backend>(f: &mut Frame<B>, app: &App, current_chat: usize, area:
             let messages_list = List::new(messages_list_item).block(
                 Block::default()
                     .borders(Borders::ALL)
-                    .title(format!("Connection {}", current_chat + 1)),
+                    .title(format!("Connection {}", current_chat)),
             );
             f.render_widget(messages_list, chunks[0]);
         }
-        None => f.render_widget(block, area),
+        None => {
+            let block = Block::default().borders(Borders::ALL).title("No chat open");
+            f.render_widget(block, chunks[0]);
+        }
     }
 
+    let input_title = match (&app.input_mode, &app.dial_error) {
+        (InputMode::Dialing, _) => "Dial (host:port)".to_string(),
+        (_, Some(err)) => format!("Input (dial failed: {})", err),
+        (_, None) => "Input".to_string(),
+    };
     let input = Paragraph::new(app.input.as_ref())
         .style(match app.input_mode {
             InputMode::Normal => Style::default(),
             InputMode::Editing => Style::default().fg(Color::Yellow),
+            InputMode::Dialing => Style::default().fg(Color::Magenta),
         })
-        .block(Block::default().borders(Borders::ALL).title("Input"));
+        .block(Block::default().borders(Borders::ALL).title(input_title));
     f.render_widget(input, chunks[1]);
 
     match app.input_mode {
@@ -250,7 +334,7 @@ fn draw_chat<B: Backend>(f: &mut Frame<B>, app: &App, current_chat: usize, area:
             // Hide the cursor. `Frame` does this by default, so we don't need to do anything here
             {}
 
-        InputMode::Editing => {
+        InputMode::Editing | InputMode::Dialing => {
             // Make the cursor visible and ask tui-rs to put it at the specified coordinates after rendering
             f.set_cursor(
                 // Put cursor past the end of the input text
@@ -1,28 +1,389 @@
 use std::{
+    cmp::Ordering,
     collections::HashMap,
+    io,
     io::{Read, Write},
+    net::SocketAddr,
     sync::{Arc, Mutex},
     thread,
 };
 
 use mio::{
     net::{TcpListener, TcpStream},
-    Events, Interest, Poll, Token, Waker,
+    Events, Interest, Poll, Registry, Token, Waker,
 };
+use rand::random;
+
+use crate::upnp::PortMapping;
+
+// Configuration for a `Server`: where to listen, and whether to try to punch
+// a hole through the local NAT so peers outside it can dial in.
+pub struct ServerConfig {
+    pub bind_addr: SocketAddr,
+    pub enable_upnp: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_addr: "0.0.0.0:13265".parse().unwrap(),
+            enable_upnp: true,
+        }
+    }
+}
 
 const SERVER: Token = Token(0);
 const NEW_CONNECTION: Token = Token(1);
+// Connection tokens are offset past the two reserved tokens above.
+const FIRST_CONNECTION_TOKEN: usize = 2;
+
+// A cap on the number of simultaneous peer connections a node will hold onto;
+// past this, new sockets are accepted and immediately dropped.
+const MAX_CONNECTIONS: usize = 64;
+
+// Messages sent over the wire are newline-delimited; `\n` may not appear
+// inside a message itself.
+const FRAME_DELIMITER: u8 = b'\n';
+
+// A cap on how large a single frame's accumulated bytes may grow before we
+// give up on ever seeing a delimiter and drop the connection, mirroring the
+// `MAX_CONNECTIONS` cap's "don't trust the remote" stance.
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+// Split all complete, newline-delimited frames off the front of `buffer`,
+// returning each frame's body with the trailing delimiter stripped. Bytes
+// after the last delimiter (a partial frame) are left in `buffer` to be
+// completed by later reads.
+fn split_frames(buffer: &mut Vec<u8>) -> Vec<Vec<u8>> {
+    let mut frames = Vec::new();
+    while let Some(pos) = buffer.iter().position(|&b| b == FRAME_DELIMITER) {
+        let frame: Vec<u8> = buffer.drain(..=pos).collect();
+        frames.push(frame[..frame.len() - 1].to_vec());
+    }
+    frames
+}
+
+// Which side of a connection dialed out: set once when the connection is
+// created and, for the loser of a simultaneous-open tie-break, overwritten
+// on the survivor below.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum HandshakeRole {
+    Initiator,
+    Responder,
+}
+
+impl HandshakeRole {
+    fn as_str(self) -> &'static str {
+        match self {
+            HandshakeRole::Initiator => "initiator",
+            HandshakeRole::Responder => "responder",
+        }
+    }
+}
+
+// Simultaneous-open negotiation state for one connection. Every connection
+// announces a `HELLO` frame carrying its own nonce, role and the address it
+// advertises itself as reachable on; the `remote_*` fields are filled in
+// once that frame arrives from the other end, at which point the
+// connection can be deduped against others to the same peer.
+struct Handshake {
+    role: HandshakeRole,
+    nonce: u64,
+    // What we tell the remote to call us; see `advertised_identity`. Fixed
+    // for the lifetime of the connection, independent of `ServerConfig`'s
+    // possibly-wildcard `bind_addr`.
+    local_identity: SocketAddr,
+    remote_identity: Option<SocketAddr>,
+    remote_nonce: Option<u64>,
+    // Whether a `HELLO` is still expected on this connection: true until the
+    // first one is accepted, and set back to true only while a tie-break
+    // re-roll is pending. Gates which incoming frames are even attempted as
+    // a handshake, so a chat message that happens to look like one isn't
+    // swallowed, and an already-identified peer can't forge a fresh `HELLO`
+    // to hijack the identity/nonce comparison later in the connection's
+    // life.
+    awaiting_hello: bool,
+}
+
+impl Handshake {
+    fn new(role: HandshakeRole, local_identity: SocketAddr) -> Self {
+        Handshake {
+            role,
+            nonce: random(),
+            local_identity,
+            remote_identity: None,
+            remote_nonce: None,
+            awaiting_hello: true,
+        }
+    }
+}
+
+// Encode the `HELLO <role> <nonce> <identity>` frame announced at the start
+// of every connection, and resent whenever a tie forces a re-roll.
+fn handshake_frame(handshake: &Handshake) -> Vec<u8> {
+    let mut frame = format!(
+        "HELLO {} {} {}",
+        handshake.role.as_str(),
+        handshake.nonce,
+        handshake.local_identity
+    )
+    .into_bytes();
+    frame.push(FRAME_DELIMITER);
+    frame
+}
+
+struct RemoteHandshake {
+    nonce: u64,
+    identity: SocketAddr,
+}
+
+// Parse a frame body as a `HELLO` handshake, if it is a well-formed one;
+// anything else yields `None` rather than an error. The read arm below only
+// calls this while a `HELLO` is expected on the connection, and a frame that
+// doesn't parse (or arrives when none is expected) is kept as an ordinary
+// chat message instead of tearing the connection down.
+fn parse_handshake_frame(body: &[u8]) -> Option<RemoteHandshake> {
+    let text = std::str::from_utf8(body).ok()?;
+    let mut parts = text.split(' ');
+    if parts.next()? != "HELLO" {
+        return None;
+    }
+    let _role = parts.next()?; // advertised for symmetry; resolution only needs the nonce and identity
+    let nonce = parts.next()?.parse().ok()?;
+    let identity = parts.next()?.parse().ok()?;
+    Some(RemoteHandshake { nonce, identity })
+}
+
+// Resolve the address we advertise to a peer as "reach me here": the
+// UPnP-mapped external address if one was obtained (actually reachable from
+// outside our network), otherwise the concrete local interface address this
+// particular socket is using. `ServerConfig::bind_addr` is intentionally
+// not used directly -- with the default `0.0.0.0` wildcard bind it's the
+// same literal value for every node, which would make every peer look
+// identical to the simultaneous-open dedup below.
+fn advertised_identity(
+    stream: &TcpStream,
+    bind_addr: SocketAddr,
+    advertise_addr: &Mutex<Option<SocketAddr>>,
+) -> SocketAddr {
+    if let Some(addr) = *advertise_addr.lock().unwrap() {
+        return addr;
+    }
+    match stream.local_addr() {
+        Ok(local) if !local.ip().is_unspecified() => SocketAddr::new(local.ip(), bind_addr.port()),
+        _ => bind_addr,
+    }
+}
+
+// Build a freshly opened connection (inbound or outbound) with its
+// handshake frame already queued up as the first thing it sends.
+fn new_connection(stream: TcpStream, role: HandshakeRole, local_identity: SocketAddr) -> Connection {
+    let handshake = Handshake::new(role, local_identity);
+    let outbound = handshake_frame(&handshake);
+    Connection {
+        stream,
+        messages: Vec::new(),
+        read_buffer: Vec::new(),
+        outbound,
+        handshake,
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    messages: Vec<String>,
+    // Bytes read off the socket that haven't yet formed a complete frame.
+    read_buffer: Vec<u8>,
+    // Framed bytes still waiting to be written out to the socket.
+    outbound: Vec<u8>,
+    handshake: Handshake,
+}
+
+// A slab-style allocator over connection slots: freed slots are tracked so
+// their token can be handed back out to the next peer instead of the slab
+// growing forever.
+#[derive(Default)]
+struct ConnectionSlab {
+    slots: Vec<Option<Connection>>,
+    free: Vec<usize>,
+    // Remote peer identity (their advertised listen address) -> the slot
+    // connected to them, populated once that connection's handshake
+    // completes. Lets a second, racing connection to the same peer be
+    // detected and deduped.
+    identities: HashMap<SocketAddr, usize>,
+}
+
+impl ConnectionSlab {
+    fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    fn insert(&mut self, connection: Connection) -> usize {
+        if let Some(index) = self.free.pop() {
+            self.slots[index] = Some(connection);
+            index
+        } else {
+            self.slots.push(Some(connection));
+            self.slots.len() - 1
+        }
+    }
+
+    fn remove(&mut self, index: usize) -> Option<Connection> {
+        let connection = self.slots.get_mut(index)?.take();
+        if let Some(connection) = &connection {
+            if let Some(identity) = connection.handshake.remote_identity {
+                if self.identities.get(&identity) == Some(&index) {
+                    self.identities.remove(&identity);
+                }
+            }
+            self.free.push(index);
+        }
+        connection
+    }
+
+    fn get_mut(&mut self, index: usize) -> Option<&mut Connection> {
+        self.slots.get_mut(index)?.as_mut()
+    }
+
+    // The indices of all live connections, in slot order, for callers (the
+    // TUI) that need to render a stable list of the connections currently up.
+    fn indices(&self) -> Vec<usize> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(index, slot)| slot.as_ref().map(|_| index))
+            .collect()
+    }
+
+    // Record that `index`'s handshake now tells us it's talking to
+    // `identity`. Returns another live connection already mapped to that
+    // identity, if this races one -- the caller runs the simultaneous-open
+    // tie-break against it.
+    fn record_identity(&mut self, index: usize, identity: SocketAddr) -> Option<usize> {
+        match self.identities.insert(identity, index) {
+            Some(previous) if previous != index => Some(previous),
+            _ => None,
+        }
+    }
+}
+
+// Both ends of a single TCP connection see the same two nonces, just
+// swapped (what we generated and what they sent us, vs. the reverse), so
+// ordering them gives every node watching a given peer-pair the same
+// tie-break verdict without another round trip.
+fn nonce_pair(a: u64, b: u64) -> (u64, u64) {
+    if a < b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+// `index`'s handshake just completed. If another live connection is already
+// mapped to the same remote identity, this is a simultaneous-open race:
+// keep only the connection with the higher nonce pair, and make it the
+// sole initiator. Equal pairs are a genuine tie -- re-roll both sides'
+// nonces and resend rather than picking a winner arbitrarily.
+fn resolve_simultaneous_open(registry: &Registry, conns: &mut ConnectionSlab, index: usize) {
+    let (identity, pair) = {
+        let connection = conns.get_mut(index).unwrap();
+        let identity = connection.handshake.remote_identity.unwrap();
+        let remote_nonce = connection.handshake.remote_nonce.unwrap();
+        (identity, nonce_pair(connection.handshake.nonce, remote_nonce))
+    };
+
+    let duplicate = match conns.record_identity(index, identity) {
+        Some(duplicate) => duplicate,
+        None => return,
+    };
+
+    let other_pair = {
+        let other = conns.get_mut(duplicate).unwrap();
+        nonce_pair(other.handshake.nonce, other.handshake.remote_nonce.unwrap())
+    };
+
+    match pair.cmp(&other_pair) {
+        Ordering::Equal => {
+            conns.identities.remove(&identity);
+            for i in [index, duplicate] {
+                let connection = conns.get_mut(i).unwrap();
+                connection.handshake.nonce = random();
+                connection.handshake.remote_nonce = None;
+                connection.handshake.awaiting_hello = true;
+                let frame = handshake_frame(&connection.handshake);
+                connection.outbound.extend_from_slice(&frame);
+                let _ = registry.reregister(
+                    &mut connection.stream,
+                    token_for(i),
+                    Interest::READABLE | Interest::WRITABLE,
+                );
+            }
+        }
+        Ordering::Greater => drop_loser(registry, conns, duplicate, index, identity),
+        Ordering::Less => drop_loser(registry, conns, index, duplicate, identity),
+    }
+}
+
+// Tear down the losing connection of a resolved simultaneous-open race and
+// make the survivor the sole initiator for this peer.
+fn drop_loser(registry: &Registry, conns: &mut ConnectionSlab, loser: usize, winner: usize, identity: SocketAddr) {
+    if let Some(mut connection) = conns.remove(loser) {
+        let _ = registry.deregister(&mut connection.stream);
+    }
+    conns.identities.insert(identity, winner);
+    if let Some(connection) = conns.get_mut(winner) {
+        connection.handshake.role = HandshakeRole::Initiator;
+    }
+}
+
+fn token_for(index: usize) -> Token {
+    Token(FIRST_CONNECTION_TOKEN + index)
+}
+
+fn index_for(token: Token) -> usize {
+    token.0 - FIRST_CONNECTION_TOKEN
+}
 
 pub struct Server {
-    pub connections: Arc<Mutex<HashMap<usize, (TcpStream, Vec<String>)>>>,
+    connections: Arc<Mutex<ConnectionSlab>>,
     pub create_connection_waker: Arc<Waker>,
+    // Addresses queued up by `dial` for the event loop to connect out to.
+    dial_queue: Arc<Mutex<Vec<SocketAddr>>>,
+    // Shared with the event loop's `Poll` so callers can (re)register interest
+    // for a connection from outside the event loop thread.
+    registry: Registry,
+    config: ServerConfig,
+    // Populated by `listen` once a UPnP mapping is in place; releasing the
+    // mapping is handled by `PortMapping`'s `Drop` impl, which `Server`'s own
+    // `Drop` triggers by taking the mapping out. The event loop thread holds
+    // a clone of this `Arc`, so dropping just this field releases nothing.
+    upnp_mapping: Arc<Mutex<Option<PortMapping>>>,
+    // The address we advertise to peers in the handshake below, once we
+    // know one: the UPnP-mapped external address, if `listen` obtained one.
+    advertise_addr: Arc<Mutex<Option<SocketAddr>>>,
+    // Taken by `listen` when the event loop thread is spawned.
+    poll: Mutex<Option<Poll>>,
 }
 
 impl Server {
-    pub fn new() -> Self {
+    pub fn new(config: ServerConfig) -> Self {
+        let poll = Poll::new().expect("Could not create polling event handler");
+        let create_connection_waker =
+            Arc::new(Waker::new(poll.registry(), NEW_CONNECTION).expect("Could not create waker"));
+        let registry = poll
+            .registry()
+            .try_clone()
+            .expect("Could not clone polling registry");
+
         Server {
-            connections: Arc::new(Mutex::new(HashMap::new())),
-            create_connection_waker: Arc::new(Waker::new),
+            connections: Arc::new(Mutex::new(ConnectionSlab::default())),
+            create_connection_waker,
+            dial_queue: Arc::new(Mutex::new(Vec::new())),
+            registry,
+            config,
+            upnp_mapping: Arc::new(Mutex::new(None)),
+            advertise_addr: Arc::new(Mutex::new(None)),
+            poll: Mutex::new(Some(poll)),
         }
     }
 }
@@ -33,37 +394,109 @@ impl Server {
         conns.len()
     }
 
+    // The slot indices of all connections currently up, in a stable order the
+    // TUI can use to build its tab list.
+    pub fn connection_indices(&self) -> Vec<usize> {
+        let conns = self.connections.lock().unwrap();
+        conns.indices()
+    }
+
+    // Queues `message` for delivery instead of writing it straight to the
+    // socket: a mio non-blocking stream can return `WouldBlock` under
+    // backpressure, and a direct `write_all` would otherwise drop it.
     pub fn send_message(&self, chat_token: usize, message: &str) {
         let mut conns = self.connections.lock().unwrap();
-        let (stream, messages) = conns.get_mut(&chat_token).unwrap();
-        if stream.write_all(message.as_bytes()).is_ok() {
-            messages.push(message.to_owned());
+        // The TUI's snapshot of which token is current can race the listener
+        // thread removing a disconnected peer's slot; just drop the message
+        // rather than panicking on a token that's no longer live.
+        let connection = match conns.get_mut(chat_token) {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        let was_idle = connection.outbound.is_empty();
+
+        connection.outbound.extend_from_slice(message.as_bytes());
+        connection.outbound.push(FRAME_DELIMITER);
+        connection.messages.push(message.to_owned());
+
+        if was_idle {
+            let _ = self.registry.reregister(
+                &mut connection.stream,
+                token_for(chat_token),
+                Interest::READABLE | Interest::WRITABLE,
+            );
         }
+        drop(conns);
+
+        // Nudge the event loop in case it's parked in `poll.poll` so the
+        // newly writable-interested connection gets serviced right away.
+        let _ = self.create_connection_waker.wake();
     }
 
     pub fn get_messages(&self, chat_token: usize) -> Option<Vec<String>> {
-        let conns = self.connections.lock().unwrap();
-        conns
-            .get(&chat_token)
-            .map(|(_, messages)| messages.to_owned())
+        let mut conns = self.connections.lock().unwrap();
+        conns.get_mut(chat_token).map(|c| c.messages.to_owned())
+    }
+
+    // Queue up an outbound connection to `addr` and wake the event loop so it
+    // can be dialed straight away instead of waiting for the next readiness event.
+    pub fn dial(&self, addr: SocketAddr) {
+        let mut queue = self.dial_queue.lock().unwrap();
+        queue.push(addr);
+        drop(queue);
+
+        let _ = self.create_connection_waker.wake();
     }
 
     pub fn listen(&self) {
         let connections = self.connections.clone();
+        let dial_queue = self.dial_queue.clone();
+        let upnp_mapping = self.upnp_mapping.clone();
+        let advertise_addr = self.advertise_addr.clone();
+        let bind_addr = self.config.bind_addr;
+        let enable_upnp = self.config.enable_upnp;
+        let mut poll = self
+            .poll
+            .lock()
+            .unwrap()
+            .take()
+            .expect("Server::listen should only be called once");
+
         thread::spawn(move || {
             let mut events = Events::with_capacity(128);
-            let addr = "127.0.0.1:13265".parse().unwrap();
-            let mut server = TcpListener::bind(addr)
-                .unwrap_or_else(|_| panic!("Could not bind TcpListener to address {}", addr));
+            let mut server = TcpListener::bind(bind_addr)
+                .unwrap_or_else(|_| panic!("Could not bind TcpListener to address {}", bind_addr));
+
+            if enable_upnp {
+                match bind_addr {
+                    SocketAddr::V4(_) => {
+                        match PortMapping::create(bind_addr.port(), "chat-p2p") {
+                            Ok((mapping, external_addr)) => {
+                                println!(
+                                    "UPnP: reachable from outside your network at {}",
+                                    external_addr
+                                );
+                                *upnp_mapping.lock().unwrap() = Some(mapping);
+                                *advertise_addr.lock().unwrap() = Some(SocketAddr::V4(external_addr));
+                            }
+                            Err(e) => eprintln!(
+                                "UPnP: no port mapping ({}), staying reachable on the local network only",
+                                e
+                            ),
+                        }
+                    }
+                    SocketAddr::V6(_) => {
+                        eprintln!("UPnP: port mapping only supports IPv4 binds, skipping")
+                    }
+                }
+            }
 
-            let poll = Poll::new().expect("Could not create polling event handler");
-            //     // Start listening for incoming connections.
+            // Start listening for incoming connections.
             poll.registry()
                 .register(&mut server, SERVER, Interest::READABLE)
                 .expect("Could not register TcpListener to event polling");
 
-            let mut socket_index = 1;
-
             // Start an event loop.
             loop {
                 // Poll Mio for events, blocking until we get an event.
@@ -76,48 +509,278 @@ impl Server {
                     // determine for which socket the event is.
                     match event.token() {
                         SERVER => {
-                            let (mut stream, _) = server
+                            let (stream, _) = server
                                 .accept()
                                 .expect("Could not establish connection with peer");
 
-                            let connection_token = Token(socket_index);
+                            let mut conns = connections.lock().unwrap();
+                            if conns.len() >= MAX_CONNECTIONS {
+                                // At capacity: drop the socket instead of growing the slab.
+                                continue;
+                            }
+
+                            let identity = advertised_identity(&stream, bind_addr, &advertise_addr);
+                            let index = conns.insert(new_connection(
+                                stream,
+                                HandshakeRole::Responder,
+                                identity,
+                            ));
+                            let connection = conns.get_mut(index).unwrap();
 
-                            // Once we have a successfull stream, we want to deregister the server from being polled as we no longer want to check for new incomming connections
                             poll.registry()
-                                .register(&mut stream, connection_token, Interest::READABLE)
+                                .register(
+                                    &mut connection.stream,
+                                    token_for(index),
+                                    Interest::READABLE | Interest::WRITABLE,
+                                )
                                 .unwrap();
+                        }
+                        // Drain addresses queued up by `Server::dial` and connect out to each of them.
+                        NEW_CONNECTION => {
+                            let addrs: Vec<SocketAddr> = {
+                                let mut queue = dial_queue.lock().unwrap();
+                                queue.drain(..).collect()
+                            };
 
-                            // Create new connection with its assocated stream and history of messages
-                            {
+                            for addr in addrs {
                                 let mut conns = connections.lock().unwrap();
-                                conns.insert(socket_index, (stream, Vec::new()));
-                            }
+                                if conns.len() >= MAX_CONNECTIONS {
+                                    continue;
+                                }
+
+                                let stream = match TcpStream::connect(addr) {
+                                    Ok(stream) => stream,
+                                    Err(_) => continue,
+                                };
+
+                                let identity =
+                                    advertised_identity(&stream, bind_addr, &advertise_addr);
+                                let index = conns.insert(new_connection(
+                                    stream,
+                                    HandshakeRole::Initiator,
+                                    identity,
+                                ));
+                                let connection = conns.get_mut(index).unwrap();
 
-                            socket_index += 1;
+                                poll.registry()
+                                    .register(
+                                        &mut connection.stream,
+                                        token_for(index),
+                                        Interest::READABLE | Interest::WRITABLE,
+                                    )
+                                    .unwrap();
+                            }
                         }
-                        // Read incoming data
-                        Token(n) => {
+                        // Service readable/writable readiness, or tear the connection
+                        // down on EOF/error.
+                        token => {
+                            let index = index_for(token);
                             let mut conns = connections.lock().unwrap();
-                            let (stream, messages) = conns.get_mut(&n).unwrap();
+                            let connection = match conns.get_mut(index) {
+                                Some(connection) => connection,
+                                None => continue,
+                            };
+
+                            let mut closed = false;
+                            let mut newly_identified = false;
 
                             if event.is_readable() {
-                                let mut buf = [0u8; 512];
-                                match stream.read(&mut buf) {
-                                    Ok(_) => messages.push(
-                                        String::from_utf8(buf.to_vec())
-                                            .unwrap()
-                                            .trim_end_matches(char::from(0))
-                                            .to_string(),
-                                    ),
-                                    Err(e) => {}
+                                let mut scratch = [0u8; 512];
+                                loop {
+                                    match connection.stream.read(&mut scratch) {
+                                        Ok(0) => {
+                                            // Peer closed the connection.
+                                            closed = true;
+                                            break;
+                                        }
+                                        Ok(n) => {
+                                            connection.read_buffer.extend_from_slice(&scratch[..n])
+                                        }
+                                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                                        Err(_) => {
+                                            closed = true;
+                                            break;
+                                        }
+                                    }
+                                }
+
+                                // A remote that never sends a delimiter would otherwise
+                                // grow this buffer forever; give up on it instead.
+                                if !closed && connection.read_buffer.len() > MAX_FRAME_SIZE {
+                                    closed = true;
+                                }
+
+                                // Only try to parse a frame as a `HELLO` while one is
+                                // actually expected -- initially, and again after a
+                                // tie-break re-roll explicitly re-arms it below. Matching
+                                // every frame for the connection's whole lifetime would
+                                // both swallow an ordinary chat message that happens to
+                                // look like a `HELLO`, and let an already-identified peer
+                                // forge a fresh one at any time to hijack the nonce/identity
+                                // comparison in `resolve_simultaneous_open`.
+                                if !closed {
+                                    for body in split_frames(&mut connection.read_buffer) {
+                                        if connection.handshake.awaiting_hello {
+                                            if let Some(remote) = parse_handshake_frame(&body) {
+                                                connection.handshake.remote_nonce =
+                                                    Some(remote.nonce);
+                                                connection.handshake.remote_identity =
+                                                    Some(remote.identity);
+                                                connection.handshake.awaiting_hello = false;
+                                                newly_identified = true;
+                                                continue;
+                                            }
+                                        }
+                                        connection
+                                            .messages
+                                            .push(String::from_utf8_lossy(&body).into_owned());
+                                    }
+
+                                    // Only the trailing partial frame is left buffered now. A
+                                    // remote that never sends a delimiter would otherwise
+                                    // grow this buffer forever; give up on it instead.
+                                    if connection.read_buffer.len() > MAX_FRAME_SIZE {
+                                        closed = true;
+                                    }
+                                }
+                            }
+
+                            if !closed && event.is_writable() {
+                                loop {
+                                    if connection.outbound.is_empty() {
+                                        break;
+                                    }
+                                    match connection.stream.write(&connection.outbound) {
+                                        Ok(0) => break,
+                                        Ok(n) => {
+                                            connection.outbound.drain(..n);
+                                        }
+                                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                                        Err(_) => {
+                                            closed = true;
+                                            break;
+                                        }
+                                    }
+                                }
+
+                                // Nothing left to send: drop WRITABLE interest so the
+                                // event loop doesn't keep waking up for it.
+                                if !closed && connection.outbound.is_empty() {
+                                    let _ = poll.registry().reregister(
+                                        &mut connection.stream,
+                                        token,
+                                        Interest::READABLE,
+                                    );
                                 }
                             }
+
+                            if closed {
+                                if let Some(mut connection) = conns.remove(index) {
+                                    let _ = poll.registry().deregister(&mut connection.stream);
+                                }
+                                continue;
+                            }
+
+                            if newly_identified {
+                                resolve_simultaneous_open(poll.registry(), &mut conns, index);
+                            }
                         }
-                        // We don't expect any events with tokens other than those we provided.
-                        _ => unreachable!(),
                     }
                 }
             }
         });
     }
 }
+
+impl Drop for Server {
+    // The event loop thread runs forever and holds its own clone of
+    // `upnp_mapping`, so dropping `Server` alone wouldn't release the
+    // mapping; take it out explicitly so `PortMapping::drop` runs now.
+    fn drop(&mut self) {
+        self.upnp_mapping.lock().unwrap().take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener as StdTcpListener, TcpStream as StdTcpStream};
+
+    // `ConnectionSlab` holds a real `Connection`, which embeds a real socket,
+    // so slab tests need a live (but otherwise unused) loopback connection
+    // rather than a mock.
+    fn loopback_connection() -> Connection {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = StdTcpStream::connect(addr).unwrap();
+        let (server_stream, _) = listener.accept().unwrap();
+        server_stream.set_nonblocking(true).unwrap();
+        new_connection(TcpStream::from_std(server_stream), HandshakeRole::Responder, addr)
+    }
+
+    #[test]
+    fn split_frames_leaves_partial_frame_buffered() {
+        let mut buffer = b"one\ntwo\nthre".to_vec();
+        let frames = split_frames(&mut buffer);
+        assert_eq!(frames, vec![b"one".to_vec(), b"two".to_vec()]);
+        assert_eq!(buffer, b"thre".to_vec());
+    }
+
+    #[test]
+    fn split_frames_with_no_delimiter_leaves_buffer_untouched() {
+        let mut buffer = b"no delimiter here".to_vec();
+        assert!(split_frames(&mut buffer).is_empty());
+        assert_eq!(buffer, b"no delimiter here".to_vec());
+    }
+
+    #[test]
+    fn nonce_pair_is_order_independent() {
+        assert_eq!(nonce_pair(3, 7), nonce_pair(7, 3));
+        assert_eq!(nonce_pair(3, 7), (3, 7));
+    }
+
+    #[test]
+    fn handshake_frame_round_trips_through_parse() {
+        let handshake =
+            Handshake::new(HandshakeRole::Initiator, "127.0.0.1:9000".parse().unwrap());
+        let nonce = handshake.nonce;
+        let mut frame = handshake_frame(&handshake);
+        assert_eq!(frame.pop(), Some(FRAME_DELIMITER));
+
+        let remote = parse_handshake_frame(&frame).expect("frame should parse as a handshake");
+        assert_eq!(remote.nonce, nonce);
+        assert_eq!(remote.identity, "127.0.0.1:9000".parse().unwrap());
+    }
+
+    #[test]
+    fn parse_handshake_frame_rejects_non_handshake_bodies() {
+        assert!(parse_handshake_frame(b"just a chat message").is_none());
+    }
+
+    #[test]
+    fn slab_reuses_freed_slots_before_growing() {
+        let mut slab = ConnectionSlab::default();
+        let a = slab.insert(loopback_connection());
+        let b = slab.insert(loopback_connection());
+        assert_eq!(slab.len(), 2);
+
+        slab.remove(a);
+        assert_eq!(slab.len(), 1);
+
+        let c = slab.insert(loopback_connection());
+        assert_eq!(c, a, "freed slot should be reused before growing the slab");
+        assert_eq!(slab.len(), 2);
+        assert_eq!(slab.indices(), vec![a, b]);
+    }
+
+    #[test]
+    fn slab_record_identity_flags_existing_mapping_as_duplicate() {
+        let mut slab = ConnectionSlab::default();
+        let identity: SocketAddr = "10.0.0.5:4000".parse().unwrap();
+        let first = slab.insert(loopback_connection());
+        let second = slab.insert(loopback_connection());
+
+        assert_eq!(slab.record_identity(first, identity), None);
+        assert_eq!(slab.record_identity(second, identity), Some(first));
+    }
+}
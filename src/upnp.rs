@@ -0,0 +1,69 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddrV4, UdpSocket},
+};
+
+use igd::{search_gateway, Gateway, PortMappingProtocol, SearchOptions};
+
+// A UPnP port mapping on the local gateway, held open for as long as this
+// value is alive. Dropping it asks the gateway to release the mapping again.
+pub struct PortMapping {
+    gateway: Gateway,
+    external_port: u16,
+}
+
+impl PortMapping {
+    // Discovers the local gateway and asks it to forward `port` on its
+    // external (WAN) interface back to this host, returning the address
+    // peers elsewhere on the internet can dial to reach us.
+    pub fn create(port: u16, description: &str) -> io::Result<(Self, SocketAddrV4)> {
+        let gateway = search_gateway(SearchOptions::default())
+            .map_err(|e| io::Error::other(e.to_string()))?;
+
+        let local_ip = local_ipv4()?;
+
+        gateway
+            .add_port(
+                PortMappingProtocol::TCP,
+                port,
+                SocketAddrV4::new(local_ip, port),
+                0,
+                description,
+            )
+            .map_err(|e| io::Error::other(e.to_string()))?;
+
+        let external_ip = gateway
+            .get_external_ip()
+            .map_err(|e| io::Error::other(e.to_string()))?;
+
+        Ok((
+            PortMapping {
+                gateway,
+                external_port: port,
+            },
+            SocketAddrV4::new(external_ip, port),
+        ))
+    }
+}
+
+impl Drop for PortMapping {
+    fn drop(&mut self) {
+        let _ = self
+            .gateway
+            .remove_port(PortMappingProtocol::TCP, self.external_port);
+    }
+}
+
+// Finds this host's LAN-facing IPv4 address by asking the OS which local
+// address it would route a UDP packet to a public address from; no packet is
+// actually sent.
+fn local_ipv4() -> io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect("8.8.8.8:80")?;
+    match socket.local_addr()?.ip() {
+        std::net::IpAddr::V4(ip) => Ok(ip),
+        std::net::IpAddr::V6(_) => Err(io::Error::other(
+            "local address is IPv6; UPnP mapping requires IPv4",
+        )),
+    }
+}